@@ -0,0 +1,88 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use rustls::{Certificate,PrivateKey};
+use peer_information_base::{PeerInformationBase};
+
+/// Which transport to dial a given peer with. Defaults to `Tls` when a
+/// peer has no explicit entry.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum TransportKind {
+    Tls,
+    Quic,
+}
+
+/// Looks up `id`'s transport in `transports`, defaulting to `Tls`. Shared by
+/// `Configuration::transport_for` and `State::connect_to`, which each only
+/// hold on to the bare map rather than a whole `Configuration`.
+pub fn transport_for(transports: &HashMap<String, TransportKind>, id: &str) -> TransportKind {
+    *transports.get(id).unwrap_or(&TransportKind::Tls)
+}
+
+/// Which side of a port forward opens the listening socket.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ForwardDirection {
+    LocalToRemote,
+    RemoteToLocal,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ForwardProtocol {
+    Tcp,
+    Udp,
+}
+
+/// A declared port forward: `listen_addr` is bound by whichever side
+/// `direction` says opens the socket, and traffic is carried to `peer_id`
+/// which connects onward to `target_addr`.
+#[derive(Clone)]
+pub struct ForwardDescriptor {
+    pub direction: ForwardDirection,
+    pub protocol: ForwardProtocol,
+    pub listen_addr: SocketAddr,
+    pub peer_id: String,
+    pub target_addr: SocketAddr,
+}
+
+pub struct Configuration {
+    pub id: String,
+    pub certificate: Certificate,
+    pub pib: PeerInformationBase,
+    pub relays: Vec<String>,
+    pub transports: HashMap<String, TransportKind>,
+    pub forwards: Vec<ForwardDescriptor>,
+    /// Local port to request an IGD mapping for, over both TCP and UDP.
+    /// Zero disables UPnP discovery.
+    pub listen_port: u16,
+    /// Announce and listen for LAN peers over UDP multicast.
+    pub lan_discovery: bool,
+    /// Dial peers discovered this way once their certificate is known.
+    pub lan_auto_connect: bool,
+    /// Address to bind the inbound mutually-authenticated TLS listener on.
+    /// `None` keeps this node client/relay-only, dialing out but never
+    /// accepting connections.
+    pub listen_addr: Option<SocketAddr>,
+    /// Private key matching `certificate`, required to run that listener.
+    pub private_key: Option<PrivateKey>,
+}
+
+impl Configuration {
+    pub fn new(id: String, certificate: Certificate) -> Configuration {
+        Configuration {
+            id: id,
+            certificate: certificate,
+            pib: PeerInformationBase::new(),
+            relays: Vec::new(),
+            transports: HashMap::new(),
+            forwards: Vec::new(),
+            listen_port: 0,
+            lan_discovery: false,
+            lan_auto_connect: false,
+            listen_addr: None,
+            private_key: None,
+        }
+    }
+
+    pub fn transport_for(&self, id: &str) -> TransportKind {
+        transport_for(&self.transports, id)
+    }
+}
@@ -0,0 +1,151 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash,Hasher};
+use std::io::Error;
+use std::net::{IpAddr,Ipv4Addr,SocketAddr};
+use std::time::Duration;
+
+use bytes::{BytesMut,BufMut,BigEndian as BytesBigEndian};
+use byteorder::{BigEndian,ByteOrder};
+use futures::{Future,Stream,Sink,future};
+use futures::sync::mpsc::channel;
+use rustls::Certificate;
+use tokio_core::net::{UdpSocket,UdpCodec};
+use tokio_timer::Timer;
+
+use gossip::{encode_socket_addr,decode_socket_addr};
+use state::State;
+
+const MULTICAST_GROUP: Ipv4Addr = Ipv4Addr::new(239, 255, 42, 99);
+const MULTICAST_PORT: u16 = 42420;
+const BEACON_INTERVAL_SECONDS: u64 = 15;
+
+struct Beacon {
+    id: String,
+    addresses: Vec<SocketAddr>,
+}
+
+fn fingerprint(cert: &Certificate) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    cert.0.hash(&mut hasher);
+    hasher.finish()
+}
+
+fn encode_beacon(id: &str, addresses: &[SocketAddr], cert: &Certificate) -> BytesMut {
+    let mut buf = BytesMut::new();
+    buf.put_u16::<BytesBigEndian>(id.len() as u16);
+    buf.put_slice(id.as_bytes());
+    buf.put_u8(addresses.len() as u8);
+    for addr in addresses {
+        encode_socket_addr(&mut buf, addr);
+    }
+    buf.put_u64::<BytesBigEndian>(fingerprint(cert));
+    buf
+}
+
+fn decode_beacon(data: &[u8]) -> Option<Beacon> {
+    if data.len() < 2 { return None; }
+    let id_len = BigEndian::read_u16(&data[0..2]) as usize;
+    let mut offset = 2;
+    if data.len() < offset + id_len + 1 { return None; }
+    let id = String::from_utf8(data[offset..offset + id_len].to_vec()).ok()?;
+    offset += id_len;
+    let count = data[offset] as usize;
+    offset += 1;
+    let mut addresses = Vec::with_capacity(count);
+    for _ in 0..count {
+        let (addr, consumed) = decode_socket_addr(&data[offset..])?;
+        addresses.push(addr);
+        offset += consumed;
+    }
+    // The trailing fingerprint is informational only: we never trust it to
+    // authenticate a peer, the TLS handshake does that once we dial.
+    if data.len() < offset + 8 {
+        return None;
+    }
+    Some(Beacon { id: id, addresses: addresses })
+}
+
+struct BeaconCodec;
+
+impl UdpCodec for BeaconCodec {
+    type In = (SocketAddr, BytesMut);
+    type Out = (SocketAddr, BytesMut);
+
+    fn decode(&mut self, src: &SocketAddr, buf: &[u8]) -> Result<Self::In, Error> {
+        Ok((*src, BytesMut::from(buf)))
+    }
+
+    fn encode(&mut self, (addr, data): Self::Out, into: &mut Vec<u8>) -> SocketAddr {
+        into.extend_from_slice(&data);
+        addr
+    }
+}
+
+/// Announces `state`'s id and addresses over a UDP multicast beacon every
+/// `BEACON_INTERVAL_SECONDS`, and listens for beacons from other uip
+/// instances on the same LAN. A beacon only ever supplies an address hint;
+/// peers are authenticated by the TLS handshake, never by the beacon.
+pub fn start(state: &State, auto_connect: bool) {
+    let handle = state.handle();
+    let bind_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), MULTICAST_PORT);
+    let socket = match UdpSocket::bind(&bind_addr, &handle) {
+        Ok(socket) => socket,
+        Err(err) => {
+            println!("Unable to bind LAN discovery socket on {}: {}", bind_addr, err);
+            return;
+        }
+    };
+    if let Err(err) = socket.join_multicast_v4(&MULTICAST_GROUP, &Ipv4Addr::UNSPECIFIED) {
+        println!("Unable to join LAN discovery multicast group: {}", err);
+        return;
+    }
+    let (sink, stream) = socket.framed(BeaconCodec).split();
+    let (beacon_sender, beacon_receiver) = channel::<(SocketAddr, BytesMut)>(4);
+    handle.spawn(beacon_receiver.forward(sink.sink_map_err(|_| ())).map(|_| ()).map_err(|_| ()));
+
+    let state2 = state.clone();
+    let listen_done = stream.for_each(move |(_from, data)| {
+        if let Some(beacon) = decode_beacon(&data) {
+            state2.handle_beacon(beacon.id, beacon.addresses, auto_connect);
+        }
+        future::ok(())
+    }).map_err(|err| println!("LAN discovery listener failed: {}", err));
+    handle.spawn(listen_done);
+
+    let state3 = state.clone();
+    let beacon_dest = SocketAddr::new(IpAddr::V4(MULTICAST_GROUP), MULTICAST_PORT);
+    let announce_done = Timer::default()
+        .interval(Duration::from_secs(BEACON_INTERVAL_SECONDS))
+        .map_err(|err| println!("LAN beacon timer failed: {}", err))
+        .for_each(move |_| {
+            let (id, addresses, certificate) = state3.identity();
+            let payload = encode_beacon(&id, &addresses, &certificate);
+            beacon_sender.clone().send((beacon_dest, payload))
+                .then(|_: Result<_, _>| Ok(()))
+        });
+    handle.spawn(announce_done);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn beacon_round_trips_id_and_addresses() {
+        let cert = Certificate(vec![1, 2, 3]);
+        let addresses = vec!["198.51.100.7:4433".parse().unwrap(), "[2001:db8::1]:4433".parse().unwrap()];
+        let encoded = encode_beacon("peer-a", &addresses, &cert);
+        let decoded = decode_beacon(&encoded).expect("decodes");
+        assert_eq!(decoded.id, "peer-a");
+        assert_eq!(decoded.addresses, addresses);
+    }
+
+    #[test]
+    fn decode_beacon_rejects_truncated_input() {
+        let cert = Certificate(vec![1, 2, 3]);
+        let addresses = vec!["198.51.100.7:4433".parse().unwrap()];
+        let mut encoded = encode_beacon("peer-a", &addresses, &cert);
+        encoded.truncate(encoded.len() - 1);
+        assert!(decode_beacon(&encoded).is_none());
+    }
+}
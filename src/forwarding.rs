@@ -0,0 +1,296 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::net::SocketAddr;
+use std::sync::{Arc,Mutex};
+
+use bytes::{BytesMut,BufMut,BigEndian as BytesBigEndian};
+use byteorder::{BigEndian,ByteOrder};
+use futures::{Future,Stream,Sink,future};
+use futures::sync::mpsc::channel;
+use futures::sync::oneshot;
+use tokio_core::net::{TcpListener,TcpStream,UdpSocket,UdpCodec};
+use tokio_io::codec::BytesCodec;
+
+use configuration::{ForwardDescriptor,ForwardDirection,ForwardProtocol};
+use state::State;
+
+/// Channel id reserved for the forwarding control protocol: asking a peer
+/// to open a connection to a `target_addr` on our behalf, carried over the
+/// same `send_frame`/`deliver_frame` path as any other channel.
+pub const CONTROL_CHANNEL: u16 = 0;
+
+const OPEN: u8 = 1;
+const OPENED: u8 = 2;
+
+fn encode_open(channel_id: u16, protocol: ForwardProtocol, target_addr: SocketAddr) -> BytesMut {
+    let target = target_addr.to_string();
+    let mut buf = BytesMut::with_capacity(6 + target.len());
+    buf.put_u8(OPEN);
+    buf.put_u16::<BytesBigEndian>(channel_id);
+    buf.put_u8(match protocol { ForwardProtocol::Tcp => 0, ForwardProtocol::Udp => 1 });
+    buf.put_u16::<BytesBigEndian>(target.len() as u16);
+    buf.put_slice(target.as_bytes());
+    buf
+}
+
+fn decode_open(data: &BytesMut) -> Option<(u16, ForwardProtocol, SocketAddr)> {
+    if data.len() < 6 || data[0] != OPEN {
+        return None;
+    }
+    let channel_id = BigEndian::read_u16(&data[1..3]);
+    let protocol = match data[3] {
+        0 => ForwardProtocol::Tcp,
+        _ => ForwardProtocol::Udp,
+    };
+    let len = BigEndian::read_u16(&data[4..6]) as usize;
+    if data.len() < 6 + len {
+        return None;
+    }
+    let target_addr = ::std::str::from_utf8(&data[6..6 + len]).ok()?.parse().ok()?;
+    Some((channel_id, protocol, target_addr))
+}
+
+/// Sent back over `CONTROL_CHANNEL` by whichever side dialed `target_addr`,
+/// once it has actually registered `channel_id` locally — the wakeup for
+/// `register_pending_open` has to cross the wire, since the two sides are
+/// separate processes with their own independent `State`.
+fn encode_opened(channel_id: u16) -> BytesMut {
+    let mut buf = BytesMut::with_capacity(3);
+    buf.put_u8(OPENED);
+    buf.put_u16::<BytesBigEndian>(channel_id);
+    buf
+}
+
+fn decode_opened(data: &BytesMut) -> Option<u16> {
+    if data.len() < 3 || data[0] != OPENED {
+        return None;
+    }
+    Some(BigEndian::read_u16(&data[1..3]))
+}
+
+/// Dispatches a frame received on `CONTROL_CHANNEL`: either an `Open`
+/// request asking us to dial `target_addr` on behalf of `peer_id` and
+/// splice the resulting connection onto `channel_id`, or an `Opened` ack
+/// telling us that `peer_id` finished registering a channel we're waiting
+/// on via `register_pending_open`.
+pub fn handle_control_frame(state: &State, peer_id: String, data: BytesMut) {
+    if let Some((channel_id, protocol, target_addr)) = decode_open(&data) {
+        match protocol {
+            ForwardProtocol::Tcp => connect_remote_tcp(state.clone(), peer_id, channel_id, target_addr),
+            ForwardProtocol::Udp => connect_remote_udp(state.clone(), peer_id, channel_id, target_addr),
+        }
+    } else if let Some(channel_id) = decode_opened(&data) {
+        state.signal_channel_opened(&peer_id, channel_id);
+    }
+}
+
+/// Starts every `LocalToRemote` forward by binding its listener locally.
+/// `RemoteToLocal` forwards need no local action: they're serviced
+/// reactively whenever the peer's matching `LocalToRemote` entry sends us
+/// an `Open` control message.
+pub fn start_forwards(state: &State, forwards: &[ForwardDescriptor]) {
+    for forward in forwards {
+        if forward.direction != ForwardDirection::LocalToRemote {
+            continue;
+        }
+        match forward.protocol {
+            ForwardProtocol::Tcp => listen_local_tcp(state.clone(), forward.clone()),
+            ForwardProtocol::Udp => listen_local_udp(state.clone(), forward.clone()),
+        }
+    }
+}
+
+fn listen_local_tcp(state: State, forward: ForwardDescriptor) {
+    let handle = state.handle();
+    let listener = match TcpListener::bind(&forward.listen_addr, &handle) {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("Unable to bind TCP forward on {}: {}", forward.listen_addr, err);
+            return;
+        }
+    };
+    let done = listener.incoming().for_each(move |(socket, _addr)| {
+        let channel_id = state.allocate_channel_id();
+        // The peer only finishes registering this channel once its own
+        // `TcpStream::connect` resolves; wait for its ack before forwarding
+        // any bytes, or they arrive at `deliver_frame` with nothing
+        // registered yet and are silently dropped.
+        let ready = state.register_pending_open(forward.peer_id.clone(), channel_id);
+        state.send_frame(forward.peer_id.clone(), CONTROL_CHANNEL, encode_open(channel_id, ForwardProtocol::Tcp, forward.target_addr));
+        pipe_tcp_stream(state.clone(), forward.peer_id.clone(), channel_id, socket, Some(ready));
+        Ok(())
+    }).map_err(|err| println!("TCP forward listener failed: {}", err));
+    handle.spawn(done);
+}
+
+fn connect_remote_tcp(state: State, peer_id: String, channel_id: u16, target_addr: SocketAddr) {
+    let handle = state.handle();
+    let state2 = state.clone();
+    let peer_id2 = peer_id.clone();
+    let connect = TcpStream::connect(&target_addr, &handle)
+        .map(move |socket| {
+            pipe_tcp_stream(state2.clone(), peer_id2.clone(), channel_id, socket, None);
+            // Tell the peer that opened this forward that we've registered
+            // the channel, so its `pipe_tcp_stream` can stop holding back
+            // the bytes it already accepted locally.
+            state2.send_frame(peer_id2.clone(), CONTROL_CHANNEL, encode_opened(channel_id));
+        })
+        .map_err(move |err| println!("Unable to dial forward target {}: {}", target_addr, err));
+    handle.spawn(connect);
+}
+
+/// Pipes `socket` onto `channel_id`. `ready`, when given, delays forwarding
+/// local reads over `send_frame` until it resolves (the channel is
+/// registered for inbound data immediately either way, since that side of
+/// the race is purely local).
+fn pipe_tcp_stream(state: State, peer_id: String, channel_id: u16, socket: TcpStream, ready: Option<oneshot::Receiver<()>>) {
+    let (sink, stream) = socket.framed(BytesCodec::new()).split();
+    let (sender, receiver) = channel::<BytesMut>(16);
+    state.register_channel(peer_id.clone(), channel_id, sender);
+    state.handle().spawn(receiver.forward(sink.sink_map_err(|_| ())).map(|_| ()).map_err(|_| ()));
+    let state2 = state.clone();
+    let peer_id2 = peer_id.clone();
+    let wait = match ready {
+        Some(receiver) => future::Either::A(receiver.map_err(|_| ())),
+        None => future::Either::B(future::ok(())),
+    };
+    let done = wait.and_then(move |_| {
+        stream.for_each(move |data| {
+            state2.send_frame(peer_id2.clone(), channel_id, data);
+            future::ok(())
+        }).map_err(|_| ())
+    });
+    state.handle().spawn(done.then(move |_: Result<(), ()>| {
+        state.deregister_channel(&peer_id, channel_id);
+        Ok(())
+    }));
+}
+
+struct DatagramCodec;
+
+impl UdpCodec for DatagramCodec {
+    type In = (SocketAddr, BytesMut);
+    type Out = (SocketAddr, BytesMut);
+
+    fn decode(&mut self, src: &SocketAddr, buf: &[u8]) -> Result<Self::In, Error> {
+        Ok((*src, BytesMut::from(buf)))
+    }
+
+    fn encode(&mut self, (addr, data): Self::Out, into: &mut Vec<u8>) -> SocketAddr {
+        into.extend_from_slice(&data);
+        addr
+    }
+}
+
+fn listen_local_udp(state: State, forward: ForwardDescriptor) {
+    let handle = state.handle();
+    let socket = match UdpSocket::bind(&forward.listen_addr, &handle) {
+        Ok(socket) => socket,
+        Err(err) => {
+            println!("Unable to bind UDP forward on {}: {}", forward.listen_addr, err);
+            return;
+        }
+    };
+    let (sink, stream) = socket.framed(DatagramCodec).split();
+    let (reply_sender, reply_receiver) = channel::<(SocketAddr, BytesMut)>(16);
+    handle.spawn(reply_receiver.forward(sink.sink_map_err(|_| ())).map(|_| ()).map_err(|_| ()));
+    let channels: Arc<Mutex<HashMap<SocketAddr, u16>>> = Arc::new(Mutex::new(HashMap::new()));
+    let done = stream.for_each(move |(source, data)| {
+        let channel_id = *channels.lock().expect("udp forward channel table poisoned")
+            .entry(source)
+            .or_insert_with(|| {
+                let channel_id = state.allocate_channel_id();
+                state.send_frame(forward.peer_id.clone(), CONTROL_CHANNEL, encode_open(channel_id, ForwardProtocol::Udp, forward.target_addr));
+                bridge_channel_to_source(&state, forward.peer_id.clone(), channel_id, source, reply_sender.clone());
+                channel_id
+            });
+        state.send_frame(forward.peer_id.clone(), channel_id, data);
+        future::ok(())
+    }).map_err(|err| println!("UDP forward listener failed: {}", err));
+    handle.spawn(done);
+}
+
+fn connect_remote_udp(state: State, peer_id: String, channel_id: u16, target_addr: SocketAddr) {
+    let handle = state.handle();
+    let socket = match UdpSocket::bind(&"0.0.0.0:0".parse().unwrap(), &handle) {
+        Ok(socket) => socket,
+        Err(err) => {
+            println!("Unable to open UDP forward socket for {}: {}", target_addr, err);
+            return;
+        }
+    };
+    let (sink, stream) = socket.framed(DatagramCodec).split();
+    let (sender, receiver) = channel::<BytesMut>(16);
+    state.register_channel(peer_id.clone(), channel_id, sender);
+    let to_target = receiver.map(move |data| (target_addr, data));
+    handle.spawn(to_target.forward(sink.sink_map_err(|_| ())).map(|_| ()).map_err(|_| ()));
+    let state2 = state.clone();
+    let done = stream.for_each(move |(_from, data)| {
+        state2.send_frame(peer_id.clone(), channel_id, data);
+        future::ok(())
+    }).map_err(|err| println!("UDP forward socket for {} closed: {}", target_addr, err));
+    handle.spawn(done);
+}
+
+/// Registers `channel_id` for `peer_id` with a small bridge task that tags
+/// every frame received for this channel with `source` and forwards it into
+/// the listener's single shared reply sink.
+fn bridge_channel_to_source(state: &State, peer_id: String, channel_id: u16, source: SocketAddr, reply_sender: ::futures::sync::mpsc::Sender<(SocketAddr, BytesMut)>) {
+    let (sender, receiver) = channel::<BytesMut>(16);
+    state.register_channel(peer_id, channel_id, sender);
+    let bridge = receiver
+        .map(move |data| (source, data))
+        .forward(reply_sender.sink_map_err(|_| ()))
+        .map(|_| ());
+    state.handle().spawn(bridge);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_round_trips_tcp_and_udp() {
+        for protocol in &[ForwardProtocol::Tcp, ForwardProtocol::Udp] {
+            let target: SocketAddr = "203.0.113.5:8080".parse().unwrap();
+            let encoded = encode_open(42, *protocol, target);
+            let (channel_id, decoded_protocol, decoded_target) = decode_open(&encoded).expect("decodes");
+            assert_eq!(channel_id, 42);
+            assert_eq!(decoded_protocol, *protocol);
+            assert_eq!(decoded_target, target);
+        }
+    }
+
+    #[test]
+    fn decode_open_rejects_truncated_input() {
+        let mut encoded = encode_open(1, ForwardProtocol::Tcp, "203.0.113.5:8080".parse().unwrap());
+        encoded.truncate(encoded.len() - 1);
+        assert!(decode_open(&encoded).is_none());
+    }
+
+    #[test]
+    fn decode_open_rejects_wrong_tag() {
+        let mut encoded = encode_open(1, ForwardProtocol::Tcp, "203.0.113.5:8080".parse().unwrap());
+        encoded[0] = 0xff;
+        assert!(decode_open(&encoded).is_none());
+    }
+
+    #[test]
+    fn opened_round_trips() {
+        let encoded = encode_opened(42);
+        assert_eq!(decode_opened(&encoded), Some(42));
+    }
+
+    #[test]
+    fn decode_opened_rejects_truncated_input() {
+        let mut encoded = encode_opened(42);
+        encoded.truncate(encoded.len() - 1);
+        assert!(decode_opened(&encoded).is_none());
+    }
+
+    #[test]
+    fn decode_opened_does_not_mistake_open_for_opened() {
+        let encoded = encode_open(1, ForwardProtocol::Tcp, "203.0.113.5:8080".parse().unwrap());
+        assert!(decode_opened(&encoded).is_none());
+    }
+}
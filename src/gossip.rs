@@ -0,0 +1,160 @@
+use std::net::{IpAddr,Ipv4Addr,Ipv6Addr,SocketAddr};
+use std::time::Duration;
+
+use bytes::{BytesMut,BufMut,BigEndian as BytesBigEndian};
+use byteorder::{BigEndian,ByteOrder};
+use futures::{Future,Stream};
+use rustls::Certificate;
+use tokio_timer::Timer;
+
+use peer_information_base::PeerEntry;
+use state::State;
+
+const GOSSIP_INTERVAL_SECONDS: u64 = 30;
+
+/// Periodically pushes our known peers down every established TLS
+/// connection so the mesh converges on a shared `PeerInformationBase`
+/// without needing everyone to be configured with every relay.
+pub fn start_gossip(state: &State) {
+    let state = state.clone();
+    let done = Timer::default()
+        .interval(Duration::from_secs(GOSSIP_INTERVAL_SECONDS))
+        .for_each(move |_| {
+            state.gossip_peers();
+            Ok(())
+        })
+        .map_err(|err| println!("Gossip timer failed: {}", err));
+    state.handle().spawn(done);
+}
+
+pub fn encode_socket_addr(buf: &mut BytesMut, addr: &SocketAddr) {
+    match *addr {
+        SocketAddr::V4(addr) => {
+            buf.put_u8(4);
+            buf.put_slice(&addr.ip().octets());
+            buf.put_u16::<BytesBigEndian>(addr.port());
+        }
+        SocketAddr::V6(addr) => {
+            buf.put_u8(6);
+            buf.put_slice(&addr.ip().octets());
+            buf.put_u16::<BytesBigEndian>(addr.port());
+        }
+    }
+}
+
+pub fn decode_socket_addr(data: &[u8]) -> Option<(SocketAddr, usize)> {
+    match data.first() {
+        Some(&4) => {
+            if data.len() < 7 { return None; }
+            let ip = Ipv4Addr::new(data[1], data[2], data[3], data[4]);
+            let port = BigEndian::read_u16(&data[5..7]);
+            Some((SocketAddr::new(IpAddr::V4(ip), port), 7))
+        }
+        Some(&6) => {
+            if data.len() < 19 { return None; }
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&data[1..17]);
+            let port = BigEndian::read_u16(&data[17..19]);
+            Some((SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port), 19))
+        }
+        _ => None,
+    }
+}
+
+pub fn encode_entries(entries: &[PeerEntry]) -> BytesMut {
+    let mut buf = BytesMut::new();
+    buf.put_u16::<BytesBigEndian>(entries.len() as u16);
+    for entry in entries {
+        buf.put_u16::<BytesBigEndian>(entry.id.len() as u16);
+        buf.put_slice(entry.id.as_bytes());
+        buf.put_u8(entry.addresses.len() as u8);
+        for addr in &entry.addresses {
+            encode_socket_addr(&mut buf, addr);
+        }
+        buf.put_u16::<BytesBigEndian>(entry.user_certificate.0.len() as u16);
+        buf.put_slice(&entry.user_certificate.0);
+    }
+    buf
+}
+
+pub fn decode_entries(data: &[u8]) -> Option<Vec<PeerEntry>> {
+    if data.len() < 2 { return None; }
+    let count = BigEndian::read_u16(&data[0..2]) as usize;
+    let mut offset = 2;
+    let mut entries = Vec::with_capacity(count);
+    for _ in 0..count {
+        if data.len() < offset + 2 { return None; }
+        let id_len = BigEndian::read_u16(&data[offset..offset + 2]) as usize;
+        offset += 2;
+        if data.len() < offset + id_len + 1 { return None; }
+        let id = String::from_utf8(data[offset..offset + id_len].to_vec()).ok()?;
+        offset += id_len;
+        let address_count = data[offset] as usize;
+        offset += 1;
+        let mut addresses = Vec::with_capacity(address_count);
+        for _ in 0..address_count {
+            let (addr, consumed) = decode_socket_addr(&data[offset..])?;
+            addresses.push(addr);
+            offset += consumed;
+        }
+        if data.len() < offset + 2 { return None; }
+        let cert_len = BigEndian::read_u16(&data[offset..offset + 2]) as usize;
+        offset += 2;
+        if data.len() < offset + cert_len { return None; }
+        let user_certificate = Certificate(data[offset..offset + cert_len].to_vec());
+        offset += cert_len;
+        entries.push(PeerEntry { id: id, addresses: addresses, user_certificate: user_certificate });
+    }
+    Some(entries)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::Certificate;
+
+    #[test]
+    fn socket_addr_round_trips_v4_and_v6() {
+        for addr in &["1.2.3.4:80".parse().unwrap(), "[::1]:443".parse().unwrap()] {
+            let mut buf = BytesMut::new();
+            encode_socket_addr(&mut buf, addr);
+            let (decoded, consumed) = decode_socket_addr(&buf).expect("decodes");
+            assert_eq!(&decoded, addr);
+            assert_eq!(consumed, buf.len());
+        }
+    }
+
+    #[test]
+    fn entries_round_trip() {
+        let entries = vec![
+            PeerEntry {
+                id: "peer-a".to_string(),
+                addresses: vec!["10.0.0.1:9000".parse().unwrap()],
+                user_certificate: Certificate(vec![1, 2, 3]),
+            },
+            PeerEntry {
+                id: "peer-b".to_string(),
+                addresses: vec![],
+                user_certificate: Certificate(vec![]),
+            },
+        ];
+        let encoded = encode_entries(&entries);
+        let decoded = decode_entries(&encoded).expect("decodes");
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].id, "peer-a");
+        assert_eq!(decoded[0].addresses, entries[0].addresses);
+        assert_eq!(decoded[0].user_certificate.0, vec![1, 2, 3]);
+        assert_eq!(decoded[1].id, "peer-b");
+    }
+
+    #[test]
+    fn decode_entries_rejects_truncated_input() {
+        let mut buf = encode_entries(&[PeerEntry {
+            id: "peer-a".to_string(),
+            addresses: vec!["10.0.0.1:9000".parse().unwrap()],
+            user_certificate: Certificate(vec![1, 2, 3]),
+        }]);
+        buf.truncate(buf.len() - 1);
+        assert!(decode_entries(&buf).is_none());
+    }
+}
@@ -8,27 +8,60 @@ extern crate tokio_io;
 extern crate tokio_core;
 extern crate tokio_rustls;
 extern crate tokio_file_unix;
+extern crate tokio_uds;
 extern crate webpki_roots;
+extern crate bytes;
+extern crate byteorder;
+extern crate quinn;
+extern crate tokio_timer;
+extern crate futures_cpupool;
 
-mod connection;
+mod transport;
 mod state;
+mod peer_information_base;
+mod configuration;
+mod unix_socket;
+mod forwarding;
+mod gossip;
+mod discovery;
+mod server;
 use state::{State};
+use configuration::Configuration;
 
-use rustls::{Certificate};
-use rustls::internal::pemfile::{ certs };
+use rustls::{Certificate,PrivateKey};
+use rustls::internal::pemfile::{ certs, rsa_private_keys };
 use std::net::ToSocketAddrs;
 use std::io::{ BufReader };
+use tokio_core::reactor::Core;
 
 fn load_certs(path: &str) -> Vec<Certificate> {
     certs(&mut BufReader::new(std::fs::File::open(path).unwrap())).unwrap()
 }
 
+fn load_private_key(path: &str) -> PrivateKey {
+    rsa_private_keys(&mut BufReader::new(std::fs::File::open(path).unwrap()))
+        .unwrap()
+        .pop()
+        .unwrap()
+}
+
 fn main() {
-    let addr = "127.0.0.1:4433".to_socket_addrs().unwrap().next().unwrap();
-    let cert = load_certs("rsa/ca.cert").pop().unwrap();
-    let state = State::new("test".to_string());
-    state.add_relay("testserver.com".to_string());
-    state.add_relay_peer("testserver.com".to_string(), addr, cert.clone());
-    state.run();
+    let relay_addr = "testserver.com:4433".to_socket_addrs().unwrap().next().unwrap();
+    let relay_cert = load_certs("rsa/ca.cert").pop().unwrap();
+    let own_cert = load_certs("rsa/own.cert").pop().unwrap();
+    let private_key = load_private_key("rsa/own.key");
+
+    let mut config = Configuration::new("test".to_string(), own_cert);
+    config.pib.add_peer("testserver.com".to_string(), vec![relay_addr], relay_cert);
+    config.relays.push("testserver.com".to_string());
+    config.listen_addr = Some("0.0.0.0:4433".to_socket_addrs().unwrap().next().unwrap());
+    config.private_key = Some(private_key);
+    config.listen_port = 4433;
+    config.lan_discovery = true;
+    config.lan_auto_connect = true;
+
+    let mut core = Core::new().unwrap();
+    let state = State::from_configuration(config, core.handle());
+    core.run(state).unwrap();
 }
 
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use rustls::Certificate;
+
+#[derive(Clone)]
+pub struct Peer {
+    pub addresses: Vec<SocketAddr>,
+    pub user_certificate: Certificate,
+}
+
+/// A single `(id, addresses, user_certificate)` row, as exchanged between
+/// peers during gossip.
+#[derive(Clone)]
+pub struct PeerEntry {
+    pub id: String,
+    pub addresses: Vec<SocketAddr>,
+    pub user_certificate: Certificate,
+}
+
+#[derive(Clone, Default)]
+pub struct PeerInformationBase {
+    peers: HashMap<String, Peer>,
+}
+
+impl PeerInformationBase {
+    pub fn new() -> PeerInformationBase {
+        PeerInformationBase { peers: HashMap::new() }
+    }
+
+    pub fn get_peer(&self, id: &str) -> Option<&Peer> {
+        self.peers.get(id)
+    }
+
+    /// Resolves a presented certificate back to the peer id it belongs to,
+    /// e.g. to attribute an inbound mTLS connection. `Certificate` has no
+    /// `PartialEq` in this rustls version, so compare the raw DER bytes.
+    pub fn find_by_certificate(&self, cert: &Certificate) -> Option<String> {
+        self.peers.iter()
+            .find(|&(_, peer)| peer.user_certificate.0 == cert.0)
+            .map(|(id, _)| id.clone())
+    }
+
+    pub fn add_peer(&mut self, id: String, addresses: Vec<SocketAddr>, user_certificate: Certificate) {
+        self.peers.insert(id, Peer { addresses: addresses, user_certificate: user_certificate });
+    }
+
+    pub fn entries(&self) -> Vec<PeerEntry> {
+        self.peers.iter()
+            .map(|(id, peer)| PeerEntry {
+                id: id.clone(),
+                addresses: peer.addresses.clone(),
+                user_certificate: peer.user_certificate.clone(),
+            })
+            .collect()
+    }
+
+    /// Merges gossiped entries into this `PeerInformationBase`, returning
+    /// the entries for peers we didn't already know about so the caller can
+    /// dial them. A gossiped entry for an id we already hold a certificate
+    /// for is only applied if it carries that *same* certificate (its
+    /// addresses may still be refreshed, e.g. a peer roaming to a new IP);
+    /// a mismatched certificate is dropped rather than trusted, otherwise
+    /// any connected peer could re-key a known id — including a configured
+    /// relay — to a certificate of its own choosing.
+    pub fn merge(&mut self, entries: Vec<PeerEntry>) -> Vec<PeerEntry> {
+        let mut new_entries = Vec::new();
+        for entry in entries {
+            match self.peers.get(&entry.id) {
+                None => {
+                    new_entries.push(entry.clone());
+                    self.peers.insert(entry.id, Peer {
+                        addresses: entry.addresses,
+                        user_certificate: entry.user_certificate,
+                    });
+                }
+                Some(known) if known.user_certificate.0 == entry.user_certificate.0 => {
+                    self.peers.get_mut(&entry.id).expect("checked by get above").addresses = entry.addresses;
+                }
+                Some(_) => {
+                    println!("Ignoring gossiped identity change for already-known peer {}", entry.id);
+                }
+            }
+        }
+        new_entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(id: &str, cert: Vec<u8>) -> PeerEntry {
+        PeerEntry { id: id.to_string(), addresses: vec!["10.0.0.1:9000".parse().unwrap()], user_certificate: Certificate(cert) }
+    }
+
+    #[test]
+    fn merge_reports_and_adds_new_peer() {
+        let mut pib = PeerInformationBase::new();
+        let new_entries = pib.merge(vec![entry("peer-a", vec![1])]);
+        assert_eq!(new_entries.len(), 1);
+        assert_eq!(pib.get_peer("peer-a").unwrap().user_certificate.0, vec![1]);
+    }
+
+    #[test]
+    fn merge_updates_addresses_for_matching_certificate() {
+        let mut pib = PeerInformationBase::new();
+        pib.merge(vec![entry("peer-a", vec![1])]);
+        let moved = PeerEntry { addresses: vec!["10.0.0.2:9000".parse().unwrap()], ..entry("peer-a", vec![1]) };
+        let new_entries = pib.merge(vec![moved.clone()]);
+        assert!(new_entries.is_empty());
+        assert_eq!(pib.get_peer("peer-a").unwrap().addresses, moved.addresses);
+    }
+
+    #[test]
+    fn merge_refuses_to_rekey_a_known_peer() {
+        let mut pib = PeerInformationBase::new();
+        pib.merge(vec![entry("relay", vec![1])]);
+        let new_entries = pib.merge(vec![entry("relay", vec![0xff])]);
+        assert!(new_entries.is_empty());
+        assert_eq!(pib.get_peer("relay").unwrap().user_certificate.0, vec![1]);
+    }
+}
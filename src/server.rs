@@ -0,0 +1,72 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use futures::{Future,Stream};
+use rustls::{ServerConfig,Certificate,PrivateKey,RootCertStore,AllowAnyAuthenticatedClient,ClientCertVerifier};
+use tokio_core::net::TcpListener;
+use tokio_rustls::ServerConfigExt;
+
+use state::State;
+use transport::Transport;
+
+/// Trusts exactly the certificates already on file in `pib`, the same way
+/// `State::connect` trusts a single peer certificate as its own root when
+/// dialing out. Rebuilt on every accepted connection so a peer learned
+/// after startup (gossip, LAN discovery) can dial in without a restart.
+fn client_cert_verifier(state: &State) -> Arc<ClientCertVerifier> {
+    let mut roots = RootCertStore::empty();
+    for cert in state.known_peer_certificates() {
+        let _ = roots.add(&cert);
+    }
+    AllowAnyAuthenticatedClient::new(roots)
+}
+
+/// Binds `listen_addr` and accepts inbound mutually-authenticated TLS
+/// connections. A connection is only handed to `Transport::from_tls_stream`
+/// once its presented client certificate resolves to a known peer in
+/// `pib`; a beacon or gossip entry alone is never enough to authenticate
+/// one, only a certificate actually proven over this handshake is.
+pub fn start(state: &State, listen_addr: SocketAddr, cert_chain: Vec<Certificate>, key: PrivateKey) {
+    let handle = state.handle();
+    let listener = match TcpListener::bind(&listen_addr, &handle) {
+        Ok(listener) => listener,
+        Err(err) => {
+            println!("Unable to bind TLS listener on {}: {}", listen_addr, err);
+            return;
+        }
+    };
+    let state = state.clone();
+    let handle2 = handle.clone();
+    let done = listener.incoming().for_each(move |(stream, peer_addr)| {
+        let mut config = ServerConfig::new(client_cert_verifier(&state));
+        if let Err(err) = config.set_single_cert(cert_chain.clone(), key.clone()) {
+            println!("Invalid server certificate/key, not accepting {}: {}", peer_addr, err);
+            return Ok(());
+        }
+        let state2 = state.clone();
+        let accept = Arc::new(config).accept_async(stream)
+            .map_err(move |err| println!("TLS handshake with {} failed: {}", peer_addr, err))
+            .and_then(move |tls_stream| {
+                let remote_id = {
+                    let (_, session) = tls_stream.get_ref();
+                    session.get_peer_certificates()
+                        .and_then(|certs| certs.first().cloned())
+                        .and_then(|cert| state2.lookup_peer_id_by_certificate(&cert))
+                };
+                match remote_id {
+                    Some(remote_id) => {
+                        println!("Accepted authenticated connection from {} ({})", remote_id, peer_addr);
+                        let conn = Transport::from_tls_stream(state2.clone(), tls_stream, remote_id.clone());
+                        state2.add_connection(remote_id, conn);
+                    }
+                    None => {
+                        println!("Rejecting {}: client certificate doesn't match a known peer", peer_addr);
+                    }
+                };
+                Ok(())
+            });
+        handle2.spawn(accept);
+        Ok(())
+    }).map_err(|err| println!("TLS listener failed: {}", err));
+    handle.spawn(done);
+}
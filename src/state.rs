@@ -1,21 +1,34 @@
-use std::net::{SocketAddr};
+use std::net::{SocketAddr,SocketAddrV4,IpAddr};
 use std::collections::HashMap;
 use std::sync::{Arc,RwLock,RwLockReadGuard,RwLockWriteGuard};
 use interfaces::{Interface,Kind};
 use futures::{Future,Poll,Async,future,Stream,Sink};
 use futures::sync::mpsc::{channel,Sender};
+use futures::sync::oneshot;
 use tokio_core::net::TcpStream;
 use rustls::{ClientConfig,Certificate,ProtocolVersion};
 use tokio_rustls::{ClientConfigExt};
 use tokio_core::reactor::{Handle};
 use tokio_uds::{UnixDatagram};
 use bytes::BytesMut;
+use quinn;
+use igd;
+use tokio_timer::Timer;
+use futures_cpupool::CpuPool;
 use std::io;
+use std::time::Duration;
+
+use rustls::PrivateKey;
 
 use transport::{Transport};
-use peer_information_base::{PeerInformationBase};
-use configuration::{Configuration};
+use peer_information_base::{PeerInformationBase,PeerEntry};
+use configuration;
+use configuration::{Configuration,TransportKind,ForwardDescriptor};
 use unix_socket::{ControlProtocolCodec,Raw};
+use forwarding;
+use gossip;
+use discovery;
+use server;
 
 #[allow(dead_code)]
 struct LocalAddress {
@@ -34,14 +47,45 @@ impl LocalAddress {
     }
 }
 
+/// How long to request an IGD port mapping lease for. Renewed by
+/// `start_igd`'s own timer well before it would expire.
+const IGD_LEASE_SECONDS: u32 = 3600;
+
+/// How often to re-request the IGD mapping, comfortably inside
+/// `IGD_LEASE_SECONDS` so a renewal always lands before the lease expires.
+const IGD_REFRESH_SECONDS: u64 = 1800;
+
 pub struct InnerState {
     pub id: String,
+    own_certificate: Certificate,
+    listen_port: u16,
     pub pib: PeerInformationBase,
     connections: HashMap<String, Vec<Transport>>,
     pub relays: Vec<String>,
+    transports: HashMap<String, TransportKind>,
+    forwards: Vec<ForwardDescriptor>,
+    forwards_started: bool,
+    gossip_started: bool,
+    lan_discovery: bool,
+    lan_auto_connect: bool,
+    lan_discovery_started: bool,
+    igd_started: bool,
+    // Addresses seen via a LAN beacon for a peer we don't yet hold a
+    // certificate for. Promoted once `pib` learns that peer's certificate
+    // through gossip.
+    lan_addresses: HashMap<String, Vec<SocketAddr>>,
+    listen_addr: Option<SocketAddr>,
+    private_key: Option<PrivateKey>,
+    server_started: bool,
+    next_channel_id: u16,
     addresses: Vec<LocalAddress>,
     sockets: HashMap<(String, u16), Sender<BytesMut>>,
+    // Signalled once the remote side of a forwarded TCP channel has
+    // actually registered it, so the local side can hold off forwarding
+    // bytes into a channel nobody is listening on yet.
+    pending_opens: HashMap<(String, u16), oneshot::Sender<()>>,
     handle: Handle,
+    quic_endpoint: Option<quinn::Endpoint>,
 }
 
 
@@ -53,12 +97,29 @@ impl State {
     pub fn from_configuration(config: Configuration, handle: Handle) -> State {
         State(Arc::new(RwLock::new(InnerState {
             id: config.id,
+            own_certificate: config.certificate,
+            listen_port: config.listen_port,
             pib: config.pib,
             connections: HashMap::new(),
             relays: config.relays,
+            transports: config.transports,
+            forwards: config.forwards,
+            forwards_started: false,
+            gossip_started: false,
+            lan_discovery: config.lan_discovery,
+            lan_auto_connect: config.lan_auto_connect,
+            lan_discovery_started: false,
+            igd_started: false,
+            lan_addresses: HashMap::new(),
+            listen_addr: config.listen_addr,
+            private_key: config.private_key,
+            server_started: false,
+            next_channel_id: forwarding::CONTROL_CHANNEL,
             addresses: Vec::new(),
             sockets: HashMap::new(),
+            pending_opens: HashMap::new(),
             handle: handle,
+            quic_endpoint: None,
         })))
     }
 
@@ -95,7 +156,79 @@ impl State {
                         .push(LocalAddress::new(interface.name.clone(), addr, None))
                 }
             }
-        };
+        }
+    }
+
+    /// Spawns the periodic IGD port-mapping task, guarded like every other
+    /// `_started` subsystem so repeated `poll` calls don't spawn it twice.
+    fn start_igd(&self) {
+        let mut state = self.write();
+        if state.igd_started {
+            return;
+        }
+        state.igd_started = true;
+        drop(state);
+        let pool = CpuPool::new(1);
+        let state = self.clone();
+        let done = Timer::default()
+            .interval(Duration::from_secs(IGD_REFRESH_SECONDS))
+            .map_err(|err| println!("IGD timer failed: {}", err))
+            .for_each(move |_| {
+                state.refresh_external_addresses(&pool);
+                Ok(())
+            });
+        self.handle().spawn(done);
+    }
+
+    /// Re-maps every discovered non-loopback IPv4 address through its
+    /// gateway on `pool`, then applies the result back on the reactor
+    /// thread once it resolves. The actual IGD/SSDP exchange is a blocking
+    /// call and must never run directly on the reactor, or it stalls every
+    /// other connection, timer and socket on the node for its duration.
+    fn refresh_external_addresses(&self, pool: &CpuPool) {
+        let listen_port = self.read().listen_port;
+        if listen_port == 0 {
+            return;
+        }
+        let internal_addrs: Vec<SocketAddrV4> = self.read().addresses.iter()
+            .filter_map(|local| match local.internal_address {
+                SocketAddr::V4(addr) => Some(addr),
+                SocketAddr::V6(_) => None,
+            })
+            .collect();
+        let state = self.clone();
+        let mapped = pool.spawn_fn(move || -> Result<Vec<(SocketAddrV4, SocketAddr)>, ()> {
+            Ok(map_addresses_via_igd(&internal_addrs, listen_port))
+        });
+        self.handle().spawn(mapped.then(move |result| {
+            if let Ok(mapped) = result {
+                state.apply_external_addresses(mapped);
+            }
+            Ok(())
+        }));
+    }
+
+    /// Records each internal address's own mapped external address (rather
+    /// than stamping every interface with whichever one happened to be
+    /// mapped first) both on its `LocalAddress` entry and in our own `pib`
+    /// entry, so gossip advertises every externally reachable address we
+    /// have.
+    fn apply_external_addresses(&self, mapped: Vec<(SocketAddrV4, SocketAddr)>) {
+        if mapped.is_empty() {
+            return;
+        }
+        let mut state = self.write();
+        for local in state.addresses.iter_mut() {
+            if let SocketAddr::V4(internal) = local.internal_address {
+                if let Some(&(_, external)) = mapped.iter().find(|&&(addr, _)| addr == internal) {
+                    local.external_address = Some(external);
+                }
+            }
+        }
+        let id = state.id.clone();
+        let certificate = state.own_certificate.clone();
+        let external_addrs: Vec<SocketAddr> = mapped.into_iter().map(|(_, external)| external).collect();
+        state.pib.add_peer(id, external_addrs, certificate);
     }
 
     fn lookup_peer(&self, id: &str) -> Option<(SocketAddr, Certificate)> {
@@ -118,13 +251,58 @@ impl State {
             };
             let relay = relay.clone();
             println!("Connecting to relay {}", relay);
-            let future = self.connect(relay.clone(), addr, cert)
+            let future = self.connect_to(relay.clone(), addr, cert)
                 .and_then(|_| future::ok(()) )
                 .map_err(move |err| println!("Unable to connect to peer {}: {}", relay, err) );
             self.read().handle.spawn(future);
         }
     }
 
+    /// Dials `id` over whichever transport `Configuration` selected for it,
+    /// defaulting to TLS-over-TCP when the peer has no explicit entry.
+    fn connect_to(&self, id: String, addr: SocketAddr, cert: Certificate) -> Box<Future<Item=Transport, Error=io::Error>> {
+        match configuration::transport_for(&self.read().transports, &id) {
+            TransportKind::Tls => Box::new(self.connect(id, addr, cert)),
+            TransportKind::Quic => self.connect_quic(id, addr, cert),
+        }
+    }
+
+    fn quic_endpoint(&self) -> quinn::Endpoint {
+        if let Some(endpoint) = self.read().quic_endpoint.clone() {
+            return endpoint;
+        }
+        let handle = self.read().handle.clone();
+        let (endpoint, _incoming) = quinn::Endpoint::builder()
+            .bind(&"0.0.0.0:0".parse().unwrap(), &handle)
+            .expect("unable to bind QUIC endpoint");
+        self.write().quic_endpoint = Some(endpoint.clone());
+        endpoint
+    }
+
+    /// Dials `id` over QUIC. `cert` and `addr` are both externally
+    /// influenced (gossip, LAN discovery), so a malformed certificate or a
+    /// handshake quinn refuses to start are reported as an `io::Error`
+    /// rather than panicking the whole reactor thread.
+    fn connect_quic(&self, id: String, addr: SocketAddr, cert: Certificate) -> Box<Future<Item=Transport, Error=io::Error>> {
+        let mut client_config = quinn::ClientConfigBuilder::default();
+        if let Err(err) = client_config.add_certificate_authority(cert) {
+            return Box::new(future::err(io::Error::new(io::ErrorKind::InvalidData, format!("invalid peer certificate: {}", err))));
+        }
+        let connecting = match self.quic_endpoint().connect_with(client_config.build(), &addr, id.as_ref()) {
+            Ok(connecting) => connecting,
+            Err(err) => return Box::new(future::err(io::Error::new(io::ErrorKind::Other, err))),
+        };
+        let state = self.clone();
+        let id2 = id.clone();
+        Box::new(connecting
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err))
+            .and_then(move |new_connection| {
+                let conn = Transport::from_quic_connection(state.clone(), new_connection.connection, id2.clone());
+                state.add_connection(id2, conn.clone());
+                Ok(conn)
+            }))
+    }
+
     fn open_ctl_socket(&self) {
         let state = self.clone();
         let done = UnixDatagram::bind("/run/user/1000/uip/ctl.sock", &self.read().handle)
@@ -148,12 +326,68 @@ impl State {
         self.read().handle.spawn(done);
     }
 
-    fn add_connection(&self, id: String, conn: Transport) {
+    pub fn add_connection(&self, id: String, conn: Transport) {
         self.write()
             .connections.entry(id).or_insert_with(Vec::new)
             .push(conn);
     }
 
+    /// Drops `conn` from the connections held for `id`, e.g. once its
+    /// heartbeat times out or its underlying stream errors, so `send_frame`
+    /// stops routing into it. Only `conn` itself is evicted — a second
+    /// connection that raced in for the same `id` before this one's
+    /// eviction fired is left alone. Relays are redialed with a
+    /// backing-off retry once `id` has no connections left at all.
+    pub fn remove_connection(&self, id: &str, conn: &Transport) {
+        let now_empty = {
+            let mut state = self.write();
+            match state.connections.get_mut(id) {
+                Some(conns) => {
+                    conns.retain(|candidate| !candidate.is_same_connection(conn));
+                    conns.is_empty()
+                }
+                None => false,
+            }
+        };
+        if now_empty {
+            let was_relay = {
+                let mut state = self.write();
+                state.connections.remove(id);
+                state.relays.iter().any(|relay| relay == id)
+            };
+            if was_relay {
+                self.reconnect_relay_with_backoff(id.to_string(), 0);
+            }
+        }
+    }
+
+    fn reconnect_relay_with_backoff(&self, relay: String, attempt: u32) {
+        let delay = Duration::from_secs(1u64.wrapping_shl(attempt.min(6)).min(60));
+        let state = self.clone();
+        let sleep = Timer::default().sleep(delay)
+            .then(move |_| {
+                let (addr, cert) = match state.lookup_peer(&relay) {
+                    Some(info) => info,
+                    None => {
+                        state.reconnect_relay_with_backoff(relay, attempt + 1);
+                        return future::ok(());
+                    }
+                };
+                println!("Reconnecting to relay {} (attempt {})", relay, attempt + 1);
+                let state2 = state.clone();
+                let relay2 = relay.clone();
+                let connect = state.connect_to(relay.clone(), addr, cert)
+                    .and_then(|_| future::ok(()))
+                    .map_err(move |err| {
+                        println!("Reconnect to relay {} failed: {}", relay2, err);
+                        state2.reconnect_relay_with_backoff(relay2.clone(), attempt + 1);
+                    });
+                state.handle().spawn(connect.then(|_: Result<(), ()>| Ok(())));
+                future::ok(())
+            });
+        self.handle().spawn(sleep);
+    }
+
     fn connect(&self, id: String, addr: SocketAddr, cert: Certificate) -> impl Future<Item=Transport, Error=io::Error> {
         let handle = self.read().handle.clone();
         let config = {
@@ -182,10 +416,226 @@ impl State {
     }
 
     pub fn deliver_frame(&self, host_id: String, channel_id: u16, data: BytesMut) {
+        if channel_id == forwarding::CONTROL_CHANNEL {
+            forwarding::handle_control_frame(self, host_id, data);
+            return;
+        }
         if let Some(socket) = self.read().sockets.get( &(host_id, channel_id) ) {
             self.read().handle.spawn(socket.clone().send(data).map(|_| ()).map_err(|_| ()));
         }
     }
+
+    /// Allocates a fresh channel id for a forwarded connection. Channel 0
+    /// is reserved for forwarding control messages (see `forwarding`).
+    pub fn allocate_channel_id(&self) -> u16 {
+        let mut state = self.write();
+        state.next_channel_id = state.next_channel_id.wrapping_add(1);
+        if state.next_channel_id == forwarding::CONTROL_CHANNEL {
+            state.next_channel_id = state.next_channel_id.wrapping_add(1);
+        }
+        state.next_channel_id
+    }
+
+    pub fn register_channel(&self, host_id: String, channel_id: u16, sender: Sender<BytesMut>) {
+        self.write().sockets.insert((host_id, channel_id), sender);
+    }
+
+    pub fn deregister_channel(&self, host_id: &str, channel_id: u16) {
+        self.write().sockets.remove(&(host_id.to_string(), channel_id));
+    }
+
+    /// Registers interest in `channel_id` being ready on `host_id`'s side,
+    /// returning a future that resolves once `signal_channel_opened` is
+    /// called for the same pair.
+    pub fn register_pending_open(&self, host_id: String, channel_id: u16) -> oneshot::Receiver<()> {
+        let (sender, receiver) = oneshot::channel();
+        self.write().pending_opens.insert((host_id, channel_id), sender);
+        receiver
+    }
+
+    /// Wakes whoever is waiting on `register_pending_open` for this pair, if
+    /// anyone is. A no-op if nobody registered (e.g. `RemoteToLocal`
+    /// forwards, which never wait).
+    pub fn signal_channel_opened(&self, host_id: &str, channel_id: u16) {
+        if let Some(sender) = self.write().pending_opens.remove(&(host_id.to_string(), channel_id)) {
+            let _ = sender.send(());
+        }
+    }
+
+    fn start_forwards(&self) {
+        let mut state = self.write();
+        if state.forwards_started {
+            return;
+        }
+        state.forwards_started = true;
+        let forwards = state.forwards.clone();
+        drop(state);
+        forwarding::start_forwards(self, &forwards);
+    }
+
+    fn start_gossip(&self) {
+        let mut state = self.write();
+        if state.gossip_started {
+            return;
+        }
+        state.gossip_started = true;
+        drop(state);
+        gossip::start_gossip(self);
+    }
+
+    /// Pushes our known peers down every established TLS connection.
+    pub fn gossip_peers(&self) {
+        let payload = gossip::encode_entries(&self.read().pib.entries());
+        for connections in self.read().connections.values() {
+            for connection in connections {
+                connection.send_peer_exchange(payload.clone());
+            }
+        }
+    }
+
+    /// Merges a gossiped peer list into our `pib`, dialing any newly-learned
+    /// peer we don't already have a connection to.
+    pub fn handle_peer_exchange(&self, from: String, data: BytesMut) {
+        let entries = match gossip::decode_entries(&data) {
+            Some(entries) => entries,
+            None => {
+                println!("Received malformed peer exchange from {}", from);
+                return;
+            }
+        };
+        let new_peers = self.write().pib.merge(entries);
+        for mut peer in new_peers {
+            self.promote_lan_address(&peer.id);
+            if let Some(pinned) = self.read().pib.get_peer(&peer.id) {
+                peer.addresses = pinned.addresses.clone();
+            }
+            self.connect_new_peer(peer);
+        }
+    }
+
+    /// Now that `id`'s certificate is actually known, folds in any address
+    /// we'd only ever seen it announce over an unauthenticated LAN beacon
+    /// (see `handle_beacon`), so a same-subnet peer we just learned about
+    /// through gossip or a relay is still reachable over the LAN directly.
+    fn promote_lan_address(&self, id: &str) {
+        let lan_addresses = match self.write().lan_addresses.remove(id) {
+            Some(addresses) => addresses,
+            None => return,
+        };
+        let mut state = self.write();
+        let mut peer = match state.pib.get_peer(id) {
+            Some(peer) => peer.clone(),
+            None => return,
+        };
+        for addr in lan_addresses {
+            if !peer.addresses.contains(&addr) {
+                peer.addresses.push(addr);
+            }
+        }
+        state.pib.add_peer(id.to_string(), peer.addresses, peer.user_certificate);
+    }
+
+    fn start_lan_discovery(&self) {
+        let mut state = self.write();
+        if state.lan_discovery_started || !state.lan_discovery {
+            return;
+        }
+        state.lan_discovery_started = true;
+        let auto_connect = state.lan_auto_connect;
+        drop(state);
+        discovery::start(self, auto_connect);
+    }
+
+    /// Our own id, best-known addresses and certificate, for announcing
+    /// ourselves over a LAN beacon.
+    pub fn identity(&self) -> (String, Vec<SocketAddr>, Certificate) {
+        let state = self.read();
+        let addresses = state.addresses.iter()
+            .map(|local| local.external_address.unwrap_or(local.internal_address))
+            .collect();
+        (state.id.clone(), addresses, state.own_certificate.clone())
+    }
+
+    /// Handles a LAN beacon from `id`. If we already hold a certificate for
+    /// that peer (learned via configuration, gossip or a relay), refresh
+    /// its addresses and optionally dial it; otherwise the address is
+    /// cached until we learn its certificate some other way, since we never
+    /// trust a beacon to authenticate a peer.
+    pub fn handle_beacon(&self, id: String, addresses: Vec<SocketAddr>, auto_connect: bool) {
+        if id == self.read().id {
+            return;
+        }
+        let certificate = self.read().pib.get_peer(&id).map(|peer| peer.user_certificate.clone());
+        match certificate {
+            Some(certificate) => {
+                // Merge rather than overwrite: a peer already known via a
+                // relay/gossip WAN address must keep that address once it's
+                // also heard over a LAN beacon, or it becomes unreachable as
+                // soon as it leaves the LAN.
+                let mut state = self.write();
+                let mut merged = state.pib.get_peer(&id).map(|peer| peer.addresses.clone()).unwrap_or_default();
+                for addr in &addresses {
+                    if !merged.contains(addr) {
+                        merged.push(*addr);
+                    }
+                }
+                state.pib.add_peer(id.clone(), merged, certificate.clone());
+                drop(state);
+                if auto_connect && !self.read().connections.contains_key(&id) {
+                    if let Some(addr) = addresses.into_iter().next() {
+                        self.connect_new_peer(PeerEntry { id: id, addresses: vec![addr], user_certificate: certificate });
+                    }
+                }
+            }
+            None => {
+                println!("Seen LAN beacon from unknown peer {}, waiting to learn its certificate", id);
+                self.write().lan_addresses.insert(id, addresses);
+            }
+        }
+    }
+
+    fn start_server(&self) {
+        let mut state = self.write();
+        if state.server_started {
+            return;
+        }
+        state.server_started = true;
+        let listen_addr = state.listen_addr;
+        let key = state.private_key.clone();
+        let cert = state.own_certificate.clone();
+        drop(state);
+        if let (Some(listen_addr), Some(key)) = (listen_addr, key) {
+            server::start(self, listen_addr, vec![cert], key);
+        }
+    }
+
+    /// Every peer certificate currently on file, for seeding the inbound
+    /// TLS listener's client-certificate verifier.
+    pub fn known_peer_certificates(&self) -> Vec<Certificate> {
+        self.read().pib.entries().into_iter().map(|entry| entry.user_certificate).collect()
+    }
+
+    /// Resolves a presented client certificate back to the peer id it
+    /// belongs to, so an inbound mTLS connection can be attributed. Callers
+    /// must reject the connection on `None` rather than accept it unnamed.
+    pub fn lookup_peer_id_by_certificate(&self, cert: &Certificate) -> Option<String> {
+        self.read().pib.find_by_certificate(cert)
+    }
+
+    fn connect_new_peer(&self, peer: PeerEntry) {
+        if self.read().connections.contains_key(&peer.id) || peer.addresses.is_empty() {
+            return;
+        }
+        let id = peer.id;
+        let addr = peer.addresses[0];
+        let cert = peer.user_certificate;
+        println!("Gossip discovered new peer {}, connecting", id);
+        let id2 = id.clone();
+        let future = self.connect_to(id.clone(), addr, cert)
+            .and_then(|_| future::ok(()))
+            .map_err(move |err| println!("Unable to connect to gossiped peer {}: {}", id2, err));
+        self.read().handle.spawn(future);
+    }
 }
 
 impl Future for State {
@@ -196,6 +646,39 @@ impl Future for State {
         self.discover_addresses();
         self.connect_to_relays();
         self.open_ctl_socket();
+        self.start_forwards();
+        self.start_gossip();
+        self.start_lan_discovery();
+        self.start_server();
+        self.start_igd();
         Ok(Async::NotReady)
     }
 }
+
+/// Pure, blocking IGD interaction for one mapping round: must run on a
+/// `CpuPool`, never directly on the tokio-core reactor thread.
+fn map_addresses_via_igd(internal_addrs: &[SocketAddrV4], listen_port: u16) -> Vec<(SocketAddrV4, SocketAddr)> {
+    let mut mapped = Vec::new();
+    for &internal_addr in internal_addrs {
+        let gateway = match igd::search_gateway(Default::default()) {
+            Ok(gateway) => gateway,
+            Err(err) => {
+                println!("No IGD gateway reachable from {}: {}", internal_addr, err);
+                continue;
+            }
+        };
+        let local_addr = SocketAddrV4::new(*internal_addr.ip(), listen_port);
+        if let Err(err) = gateway.add_port(igd::PortMappingProtocol::TCP, listen_port, local_addr, IGD_LEASE_SECONDS, "uip") {
+            println!("Unable to map TCP port via IGD: {}", err);
+            continue;
+        }
+        if let Err(err) = gateway.add_port(igd::PortMappingProtocol::UDP, listen_port, local_addr, IGD_LEASE_SECONDS, "uip (QUIC)") {
+            println!("Unable to map UDP port via IGD: {}", err);
+        }
+        match gateway.get_external_ip() {
+            Ok(ip) => mapped.push((internal_addr, SocketAddr::new(IpAddr::V4(ip), listen_port))),
+            Err(err) => println!("Unable to fetch external address from gateway: {}", err),
+        }
+    }
+    mapped
+}
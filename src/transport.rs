@@ -2,18 +2,28 @@ use tokio_rustls::{TlsStream};
 use tokio_core::net::TcpStream;
 use tokio_io::codec::{Encoder,Decoder};
 use tokio_io::{AsyncRead};
+use tokio_io::io::{read_exact,write_all};
 use rustls::{Session};
 use std::io::{Error,ErrorKind};
+use std::collections::HashMap;
+use std::sync::{Arc,Mutex};
+use std::time::{Duration,Instant};
 use futures::{Stream,Sink,Future};
-use futures::sync::mpsc::{Sender,SendError,channel};
+use futures::sync::mpsc::{Sender,channel};
 use bytes::{BytesMut, BufMut, BigEndian as BytesBigEndian};
 use byteorder::{BigEndian,ByteOrder};
+use tokio_timer::Timer;
+use quinn;
 use state::State;
 
+const PING_INTERVAL_SECONDS: u64 = 10;
+const PONG_TIMEOUT_SECONDS: u64 = 30;
+
 pub enum Frame {
     Ping,
     Pong,
-    Data(u16, BytesMut)
+    Data(u16, BytesMut),
+    PeerExchange(BytesMut),
 }
 
 pub struct Codec();
@@ -32,6 +42,12 @@ impl Encoder for Codec {
                 dst.put_u16::<BytesBigEndian>(data.len() as u16);
                 dst.put(data);
             }
+            Frame::PeerExchange(data) => {
+                dst.put_u8(4);
+                dst.put_u16::<BytesBigEndian>(0); // reserved, keeps the 5-byte header shared with Data
+                dst.put_u16::<BytesBigEndian>(data.len() as u16);
+                dst.put(data);
+            }
         };
         Ok(())
     }
@@ -49,7 +65,7 @@ impl Decoder for Codec {
         match typ {
             1 => return Ok(Some(Frame::Ping)),
             2 => return Ok(Some(Frame::Pong)),
-            3 => {},
+            3 | 4 => {},
             _ => return Err(Error::new(ErrorKind::InvalidData, "invalid message type"))
         };
         if src.len() < 5 {
@@ -61,14 +77,52 @@ impl Decoder for Codec {
             return Ok(None);
         }
         src.split_to(5);
-        Ok(Some(Frame::Data(app_id, src.split_off(length))))
+        let data = src.split_off(length);
+        Ok(Some(if typ == 4 { Frame::PeerExchange(data) } else { Frame::Data(app_id, data) }))
     }
 }
 
+#[derive(Clone)]
+enum Backend {
+    Tls {
+        sink: Sender<Frame>,
+        // Updated whenever a `Pong` arrives; the heartbeat task evicts the
+        // connection once this goes stale for longer than `PONG_TIMEOUT_SECONDS`.
+        last_pong: Arc<Mutex<Instant>>,
+    },
+    Quic {
+        connection: quinn::Connection,
+        // Lazily-opened, per-channel unidirectional send streams, each fed
+        // by a single writer task (see `send_quic_frame`) so that opening
+        // the stream and writing to it stay serialized per channel instead
+        // of racing two `open_uni()` calls for the same `channel_id`.
+        streams: Arc<Mutex<HashMap<u16, Sender<BytesMut>>>>,
+    },
+}
+
 #[derive(Clone)]
 pub struct Transport {
     state: State,
-    sink: Sender<Frame>,
+    backend: Backend,
+    // Shared only by clones of the same logical connection, so
+    // `is_same_connection` can tell a connection apart from a second one
+    // that raced in for the same peer id.
+    id: Arc<()>,
+}
+
+fn write_channel_header(stream: quinn::SendStream, channel_id: u16) -> impl Future<Item=quinn::SendStream, Error=Error> {
+    let mut header = BytesMut::with_capacity(2);
+    header.put_u16::<BytesBigEndian>(channel_id);
+    write_all(stream, header).map(|(stream, _)| stream)
+}
+
+fn write_channel_data(stream: quinn::SendStream, data: BytesMut) -> impl Future<Item=quinn::SendStream, Error=Error> {
+    write_all(stream, data).map(|(stream, _)| stream)
+}
+
+fn read_channel_header(stream: quinn::RecvStream) -> impl Future<Item=(u16, quinn::RecvStream), Error=Error> {
+    read_exact(stream, [0u8; 2])
+        .map(|(stream, header)| (BigEndian::read_u16(&header), stream))
 }
 
 impl Transport {
@@ -76,28 +130,185 @@ impl Transport {
         let (sink, stream) = stream.framed(Codec()).split();
         let (sender, receiver) = channel::<Frame>(10);
         state.handle().spawn(receiver.forward(sink.sink_map_err(|_|())).map(|_| ()).map_err(|_| ()));
+        let last_pong = Arc::new(Mutex::new(Instant::now()));
         let transport = Transport {
             state: state.clone(),
-            sink: sender,
+            backend: Backend::Tls { sink: sender, last_pong: last_pong.clone() },
+            id: Arc::new(()),
         };
         let transport2 = transport.clone();
+        let remote_id2 = remote_id.clone();
         let done = stream.for_each(move |frame| {
             match frame {
-                Frame::Ping => println!("Ping"),
-                Frame::Pong => println!("Pong"),
+                Frame::Ping => transport2.send_pong(),
+                Frame::Pong => *last_pong.lock().expect("pong timestamp poisoned") = Instant::now(),
                 Frame::Data(channel_id, data) => {
-                    transport2.state.deliver_frame(remote_id.clone(), channel_id, data)
+                    transport2.state.deliver_frame(remote_id2.clone(), channel_id, data)
+                }
+                Frame::PeerExchange(data) => {
+                    transport2.state.handle_peer_exchange(remote_id2.clone(), data)
                 }
             };
             Ok(())
         });
-        state.handle().spawn(done.map_err(|_| ()));
+        let state2 = state.clone();
+        let remote_id3 = remote_id.clone();
+        let transport3 = transport.clone();
+        state.handle().spawn(done.then(move |_: Result<(), Error>| {
+            state2.remove_connection(&remote_id3, &transport3);
+            Ok(())
+        }));
+        start_heartbeat(transport.clone(), state, remote_id);
         return transport;
     }
 
-    pub fn send_frame(&self, channel_id: u16, data: BytesMut) -> impl Future<Item=Sender<Frame>,Error=SendError<Frame>>{
-        self.sink.clone()
-            .send(Frame::Data(channel_id, data))
+    /// Carries a peer connection over a single QUIC connection, handing
+    /// each `channel_id` its own unidirectional QUIC stream
+    /// rather than multiplexing everything through `Frame::Data`. Ping/Pong
+    /// keepalive stays on the TLS path; QUIC relies on its own idle timeout.
+    pub fn from_quic_connection(state: State, connection: quinn::Connection, remote_id: String) -> Transport {
+        let transport = Transport {
+            state: state.clone(),
+            backend: Backend::Quic {
+                connection: connection.clone(),
+                streams: Arc::new(Mutex::new(HashMap::new())),
+            },
+            id: Arc::new(()),
+        };
+        let remote_id2 = remote_id.clone();
+        let done = connection.incoming_streams()
+            .map_err(|err| Error::new(ErrorKind::Other, err))
+            .for_each(move |recv| {
+                let state = state.clone();
+                let remote_id = remote_id2.clone();
+                read_channel_header(recv).and_then(move |(channel_id, recv)| {
+                    let state = state.clone();
+                    recv.for_each(move |data| {
+                        state.deliver_frame(remote_id.clone(), channel_id, data);
+                        Ok(())
+                    })
+                })
+            });
+        let state2 = transport.state.clone();
+        let remote_id3 = remote_id.clone();
+        let transport2 = transport.clone();
+        transport.state.handle().spawn(done.then(move |result| {
+            if let Err(err) = result {
+                println!("QUIC connection to {} closed: {}", remote_id3, err);
+            }
+            state2.remove_connection(&remote_id3, &transport2);
+            Ok(())
+        }));
+        transport
+    }
+
+    /// Gossips a serialized peer list down this connection. Only
+    /// meaningful on the TLS backend, since `Frame` is TLS-specific.
+    pub fn send_peer_exchange(&self, payload: BytesMut) {
+        if let Backend::Tls { ref sink, .. } = self.backend {
+            let send = sink.clone().send(Frame::PeerExchange(payload))
+                .map(|_| ())
+                .map_err(|_| ());
+            self.state.handle().spawn(send);
+        }
     }
 
+    pub fn send_frame(&self, channel_id: u16, data: BytesMut) {
+        match self.backend {
+            Backend::Tls { ref sink, .. } => {
+                let send = sink.clone().send(Frame::Data(channel_id, data))
+                    .map(|_| ())
+                    .map_err(|_| ());
+                self.state.handle().spawn(send);
+            }
+            Backend::Quic { ref connection, ref streams } => {
+                self.send_quic_frame(connection.clone(), streams.clone(), channel_id, data);
+            }
+        }
+    }
+
+    fn send_ping(&self) {
+        if let Backend::Tls { ref sink, .. } = self.backend {
+            let send = sink.clone().send(Frame::Ping).map(|_| ()).map_err(|_| ());
+            self.state.handle().spawn(send);
+        }
+    }
+
+    fn send_pong(&self) {
+        if let Backend::Tls { ref sink, .. } = self.backend {
+            let send = sink.clone().send(Frame::Pong).map(|_| ()).map_err(|_| ());
+            self.state.handle().spawn(send);
+        }
+    }
+
+    /// Used by `State::remove_connection` to evict exactly the `Transport`
+    /// whose heartbeat timed out or whose stream closed, rather than every
+    /// connection held for the same peer id — a second, healthy connection
+    /// may have raced in for that id before the stale one's eviction fired.
+    pub fn is_same_connection(&self, other: &Transport) -> bool {
+        Arc::ptr_eq(&self.id, &other.id)
+    }
+
+    fn last_pong_elapsed(&self) -> Option<Duration> {
+        match self.backend {
+            Backend::Tls { ref last_pong, .. } => Some(last_pong.lock().expect("pong timestamp poisoned").elapsed()),
+            Backend::Quic { .. } => None,
+        }
+    }
+
+    /// Queues `data` onto `channel_id`'s writer task, spawning that task the
+    /// first time this channel is used. Funnelling every write for a
+    /// channel through one task's `fold` keeps `open_uni()` and the writes
+    /// that follow it strictly ordered — two `send_frame` calls issued
+    /// before the stream finishes opening no longer race each other into
+    /// opening a second stream and splitting the channel's bytes across it.
+    fn send_quic_frame(&self, connection: quinn::Connection, streams: Arc<Mutex<HashMap<u16, Sender<BytesMut>>>>, channel_id: u16, data: BytesMut) {
+        let sender = streams.lock().expect("quic stream table poisoned")
+            .entry(channel_id)
+            .or_insert_with(|| spawn_quic_channel_writer(self.state.clone(), connection.clone(), channel_id))
+            .clone();
+        let send = sender.send(data).map(|_| ()).map_err(|_| ());
+        self.state.handle().spawn(send);
+    }
+}
+
+/// Spawns the single task that owns `channel_id`'s `SendStream`: opens it,
+/// writes the channel header, then folds every queued `BytesMut` through it
+/// in order for as long as the channel lives.
+fn spawn_quic_channel_writer(state: State, connection: quinn::Connection, channel_id: u16) -> Sender<BytesMut> {
+    let (sender, receiver) = channel::<BytesMut>(16);
+    let opened = connection.open_uni()
+        .map_err(|err| Error::new(ErrorKind::Other, err))
+        .and_then(move |stream| write_channel_header(stream, channel_id));
+    let written = opened.and_then(move |stream| {
+        receiver
+            .map_err(|_| Error::new(ErrorKind::Other, "quic channel sender dropped"))
+            .fold(stream, |stream, data| write_channel_data(stream, data))
+    }).map(|_| ()).map_err(move |err| println!("QUIC channel {} writer failed: {}", channel_id, err));
+    state.handle().spawn(written);
+    sender
+}
+
+/// Sends a `Ping` every `PING_INTERVAL_SECONDS` and evicts `remote_id`'s
+/// connection from `State` once a `PONG_TIMEOUT_SECONDS`-long silence
+/// follows, so `send_frame` stops routing into a connection that's gone
+/// dark after a NAT/relay link silently drops.
+fn start_heartbeat(transport: Transport, state: State, remote_id: String) {
+    let done = Timer::default()
+        .interval(Duration::from_secs(PING_INTERVAL_SECONDS))
+        .map_err(|err| Error::new(ErrorKind::Other, err))
+        .for_each(move |_| {
+            transport.send_ping();
+            match transport.last_pong_elapsed() {
+                Some(elapsed) if elapsed > Duration::from_secs(PONG_TIMEOUT_SECONDS) => {
+                    println!("Peer {} timed out, evicting connection", remote_id);
+                    state.remove_connection(&remote_id, &transport);
+                    Err(Error::new(ErrorKind::TimedOut, "pong timeout"))
+                }
+                _ => Ok(()),
+            }
+        })
+        .map(|_| ())
+        .map_err(|_| ());
+    transport.state.handle().spawn(done);
 }
@@ -0,0 +1,65 @@
+use tokio_io::codec::{Encoder,Decoder};
+use bytes::{BytesMut,BufMut};
+use byteorder::{BigEndian as ByteOrderBigEndian,ByteOrder};
+use std::io::{Error,ErrorKind};
+
+/// Decodes control messages sent down the unix control socket: a client
+/// announces the local path it wants bridged to a `(host_id, channel_id)`
+/// pair on the overlay.
+pub struct ControlProtocolCodec;
+
+impl Decoder for ControlProtocolCodec {
+    type Item = (String, String, u16);
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<(String, String, u16)>, Error> {
+        if src.len() < 2 {
+            return Ok(None);
+        }
+        let path_len = ByteOrderBigEndian::read_u16(&src[0..2]) as usize;
+        if src.len() < 2 + path_len + 2 {
+            return Ok(None);
+        }
+        let host_id_len = ByteOrderBigEndian::read_u16(&src[2 + path_len..4 + path_len]) as usize;
+        let total = 4 + path_len + host_id_len + 2;
+        if src.len() < total {
+            return Ok(None);
+        }
+        src.split_to(2);
+        let path = String::from_utf8(src.split_to(path_len).to_vec())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid utf8 path"))?;
+        src.split_to(2);
+        let host_id = String::from_utf8(src.split_to(host_id_len).to_vec())
+            .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid utf8 host id"))?;
+        let channel_id = ByteOrderBigEndian::read_u16(&src[0..2]);
+        src.split_to(2);
+        Ok(Some((path, host_id, channel_id)))
+    }
+}
+
+/// Passes datagrams through untouched; used to frame the raw application
+/// bytes exchanged with a bridged unix datagram socket.
+pub struct Raw;
+
+impl Encoder for Raw {
+    type Item = BytesMut;
+    type Error = Error;
+
+    fn encode(&mut self, item: BytesMut, dst: &mut BytesMut) -> Result<(), Error> {
+        dst.put(item);
+        Ok(())
+    }
+}
+
+impl Decoder for Raw {
+    type Item = BytesMut;
+    type Error = Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<BytesMut>, Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        let len = src.len();
+        Ok(Some(src.split_to(len)))
+    }
+}